@@ -0,0 +1,187 @@
+//! permessage-deflate (RFC 7692) compression for data message payloads.
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use thiserror::Error;
+
+/// The 4-byte trailer a SYNC_FLUSH DEFLATE block ends with; permessage-deflate
+/// strips it from outgoing payloads and restores it before inflating.
+const SYNC_FLUSH_TRAILER: [u8; 4] = [0x00, 0x00, 0xFF, 0xFF];
+
+/// Error returned by [`PerMessageDeflate::decompress`].
+#[derive(Debug, Error)]
+pub(crate) enum DecompressError {
+    /// The DEFLATE stream was corrupt or truncated.
+    #[error("DEFLATE decompression failed: {0}")]
+    Inflate(#[from] flate2::DecompressError),
+    /// The decompressed payload exceeded `max_len`, so decompression was
+    /// abandoned before growing the output buffer any further.
+    #[error("decompressed payload exceeds the {limit} byte limit")]
+    TooLarge {
+        /// The limit that was exceeded.
+        limit: u64,
+    },
+}
+
+/// Per-connection permessage-deflate compression/decompression state.
+pub(crate) struct PerMessageDeflate {
+    compress: Compress,
+    decompress: Decompress,
+    compress_no_context_takeover: bool,
+    decompress_no_context_takeover: bool,
+}
+
+impl PerMessageDeflate {
+    /// Creates compression state for this side of the connection, given the
+    /// negotiated context-takeover settings for the outgoing and incoming
+    /// directions.
+    pub(crate) fn new(compress_no_context_takeover: bool, decompress_no_context_takeover: bool) -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            compress_no_context_takeover,
+            decompress_no_context_takeover,
+        }
+    }
+
+    /// Compresses a full message payload, stripping the trailing sync-flush
+    /// marker as required by permessage-deflate.
+    ///
+    /// `compress_vec` only fills the output `Vec`'s current spare capacity
+    /// and returns `Ok` even when more output remains, so this loops,
+    /// growing the buffer, until the sync-flush has fully drained.
+    pub(crate) fn compress(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(input.len() + 16);
+        let mut remaining = input;
+        loop {
+            let in_before = self.compress.total_in();
+            let out_before = self.compress.total_out();
+            self.compress
+                .compress_vec(remaining, &mut output, FlushCompress::Sync)
+                .expect("in-memory deflate compression cannot fail");
+            let consumed = (self.compress.total_in() - in_before) as usize;
+            remaining = &remaining[consumed..];
+            let produced = self.compress.total_out() - out_before;
+            if remaining.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            if consumed == 0 && produced == 0 {
+                output.reserve(output.capacity().max(16));
+            }
+        }
+        output.truncate(output.len().saturating_sub(SYNC_FLUSH_TRAILER.len()));
+        if self.compress_no_context_takeover {
+            self.compress.reset();
+        }
+        output
+    }
+
+    /// Decompresses a full message payload, restoring the trailing
+    /// sync-flush marker that the sender stripped.
+    ///
+    /// Sync-flushed permessage-deflate streams never set the DEFLATE final
+    /// block bit, so `decompress_vec` never reports `Status::StreamEnd` for
+    /// them; it only fills the output `Vec`'s current spare capacity and
+    /// returns `Ok` even when more output remains. This loops, growing the
+    /// buffer, until the input is fully consumed and a call leaves spare
+    /// output capacity unused (proof the flush has actually drained),
+    /// instead of assuming one call drains it.
+    ///
+    /// `max_len` bounds the decompressed size: a compression ratio of
+    /// 1000x or more is routine, so a small compressed frame under
+    /// `max_frame_size` can otherwise inflate to an unbounded allocation.
+    /// Decompression is abandoned as soon as `output` exceeds `max_len`,
+    /// before it is allowed to grow any further.
+    pub(crate) fn decompress(&mut self, input: &[u8], max_len: u64) -> Result<Vec<u8>, DecompressError> {
+        let mut padded = Vec::with_capacity(input.len() + SYNC_FLUSH_TRAILER.len());
+        padded.extend_from_slice(input);
+        padded.extend_from_slice(&SYNC_FLUSH_TRAILER);
+
+        let cap_limit = max_len.saturating_add(1).min(usize::MAX as u64) as usize;
+        let mut output = Vec::with_capacity((input.len() * 4).max(32).min(cap_limit));
+        let mut remaining = padded.as_slice();
+        loop {
+            let in_before = self.decompress.total_in();
+            let status =
+                self.decompress
+                    .decompress_vec(remaining, &mut output, FlushDecompress::Sync)?;
+            let consumed = (self.decompress.total_in() - in_before) as usize;
+            remaining = &remaining[consumed..];
+            if output.len() as u64 > max_len {
+                return Err(DecompressError::TooLarge { limit: max_len });
+            }
+            if status == flate2::Status::StreamEnd {
+                break;
+            }
+            if remaining.is_empty() && output.len() < output.capacity() {
+                break;
+            }
+            if output.len() == output.capacity() {
+                output.reserve(output.capacity().max(32));
+            }
+        }
+        if self.decompress_no_context_takeover {
+            self.decompress.reset(false);
+        }
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_small_payload() {
+        let mut tx = PerMessageDeflate::new(false, false);
+        let mut rx = PerMessageDeflate::new(false, false);
+        let compressed = tx.compress(b"hello world");
+        let decompressed = rx.decompress(&compressed, u64::MAX).unwrap();
+        assert_eq!(decompressed, b"hello world");
+    }
+
+    #[test]
+    fn round_trips_payload_larger_than_initial_capacity_guess() {
+        let mut tx = PerMessageDeflate::new(false, false);
+        let mut rx = PerMessageDeflate::new(false, false);
+        let input = vec![b'a'; 100_000];
+        let compressed = tx.compress(&input);
+        let decompressed = rx.decompress(&compressed, u64::MAX).unwrap();
+        assert_eq!(decompressed, input);
+    }
+
+    #[test]
+    fn round_trips_across_multiple_messages_with_context_takeover() {
+        let mut tx = PerMessageDeflate::new(false, false);
+        let mut rx = PerMessageDeflate::new(false, false);
+        for msg in [&b"first message"[..], b"second message", b"third"] {
+            let compressed = tx.compress(msg);
+            let decompressed = rx.decompress(&compressed, u64::MAX).unwrap();
+            assert_eq!(decompressed, msg);
+        }
+    }
+
+    #[test]
+    fn round_trips_with_no_context_takeover() {
+        let mut tx = PerMessageDeflate::new(true, true);
+        let mut rx = PerMessageDeflate::new(true, true);
+        for msg in [&b"first message"[..], b"second message"] {
+            let compressed = tx.compress(msg);
+            let decompressed = rx.decompress(&compressed, u64::MAX).unwrap();
+            assert_eq!(decompressed, msg);
+        }
+    }
+
+    #[test]
+    fn decompress_rejects_payload_exceeding_max_len() {
+        let mut tx = PerMessageDeflate::new(false, false);
+        let mut rx = PerMessageDeflate::new(false, false);
+        // A highly compressible run, so the compressed frame easily fits
+        // under a small `max_frame_size` while the inflated size does not.
+        let input = vec![b'a'; 100_000];
+        let compressed = tx.compress(&input);
+        assert!(compressed.len() < 1_000);
+
+        let err = rx.decompress(&compressed, 1_000).unwrap_err();
+        assert!(matches!(err, DecompressError::TooLarge { limit: 1_000 }));
+    }
+}
@@ -5,21 +5,29 @@ use std::convert::TryFrom;
 
 use crate::{Error, Result};
 
+/// The default maximum frame payload size, in bytes, used unless overridden
+/// via [`WebSocket::with_max_frame_size`](crate::WebSocket::with_max_frame_size).
+pub const DEFAULT_MAX_FRAME_SIZE: u64 = 64 * 1024;
+
 /// Represents the opcode of a WebSocket frame.
+///
+/// Discriminants are pinned to the RFC 6455 wire values: [`Frame::to_bytes`]
+/// casts `opcode as u8` directly into the frame header, so these must match
+/// what `OpCode`'s `TryFrom<u8>` impl parses back.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
     /// Indicates a continuation frame.
-    Continuation,
+    Continuation = 0,
     /// Indicates a text frame.
-    Text,
+    Text = 1,
     /// Indicates a binary frame.
-    Binary,
+    Binary = 2,
     /// Indicates a close frame.
-    Close,
+    Close = 8,
     /// Indicates a ping frame.
-    Ping,
+    Ping = 9,
     /// Indicates a pong frame.
-    Pong,
+    Pong = 10,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -43,6 +51,167 @@ impl TryFrom<u8> for OpCode {
     }
 }
 
+/// RFC 6455 WebSocket close status codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCode {
+    /// Normal closure; the purpose for which the connection was established has been fulfilled.
+    Normal,
+    /// The endpoint is terminating the connection due to a protocol error.
+    ProtocolError,
+    /// The endpoint received data it couldn't accept, such as non-UTF-8 data in a text message.
+    InvalidData,
+    /// The endpoint received a message that violates its policy.
+    PolicyViolation,
+    /// The server encountered an unexpected condition that prevented it from fulfilling the request.
+    InternalError,
+    /// Any other close code not explicitly modeled above.
+    Other(u16),
+}
+
+impl CloseCode {
+    /// Converts this close code to its big-endian wire representation.
+    pub fn to_be_bytes(self) -> [u8; 2] {
+        u16::from(self).to_be_bytes()
+    }
+
+    /// Parses a close code from its big-endian wire representation.
+    pub fn from_be_bytes(bytes: [u8; 2]) -> Self {
+        Self::from(u16::from_be_bytes(bytes))
+    }
+}
+
+impl From<CloseCode> for u16 {
+    fn from(code: CloseCode) -> u16 {
+        match code {
+            CloseCode::Normal => 1000,
+            CloseCode::ProtocolError => 1002,
+            CloseCode::InvalidData => 1003,
+            CloseCode::PolicyViolation => 1008,
+            CloseCode::InternalError => 1011,
+            CloseCode::Other(code) => code,
+        }
+    }
+}
+
+impl From<u16> for CloseCode {
+    fn from(value: u16) -> Self {
+        match value {
+            1000 => CloseCode::Normal,
+            1002 => CloseCode::ProtocolError,
+            1003 => CloseCode::InvalidData,
+            1008 => CloseCode::PolicyViolation,
+            1011 => CloseCode::InternalError,
+            other => CloseCode::Other(other),
+        }
+    }
+}
+
+/// The code and reason carried by a `Close` frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    /// The close status code.
+    pub code: CloseCode,
+    /// The UTF-8 reason string, if any.
+    pub reason: String,
+}
+
+/// A parsed WebSocket frame header, decoded independently of any I/O source.
+///
+/// Exposed so advanced users can drive parsing from their own buffered byte
+/// source (e.g. a ring buffer fed by a different transport) instead of
+/// [`Frame::read_from`].
+#[derive(Debug, Clone, Copy)]
+pub struct FrameHeader {
+    /// Indicates if this is the final fragment in a message.
+    pub fin: bool,
+    /// First reserved bit.
+    pub rsv1: bool,
+    /// Second reserved bit.
+    pub rsv2: bool,
+    /// Third reserved bit.
+    pub rsv3: bool,
+    /// The opcode for this frame.
+    pub opcode: OpCode,
+    /// The masking key, if any.
+    pub mask: Option<[u8; 4]>,
+    /// The length of the frame's payload, in bytes.
+    pub payload_len: u64,
+}
+
+impl FrameHeader {
+    /// The largest a frame header can be: 2 base bytes, 8 extended-length
+    /// bytes, and a 4-byte mask key.
+    const MAX_LEN: usize = 14;
+
+    /// Tries to parse a frame header from the start of `buf`.
+    ///
+    /// Returns `Ok(None)` if `buf` does not yet hold a complete header; the
+    /// caller should append more bytes and try again. On success, returns
+    /// the parsed header along with the number of bytes of `buf` it
+    /// consumed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the header's opcode is invalid. This does not
+    /// check `payload_len` against any size limit; callers that enforce one
+    /// (such as [`Frame::read_from`]) do so themselves once the header is
+    /// parsed.
+    pub fn try_parse(buf: &[u8]) -> Result<Option<(Self, usize)>> {
+        if buf.len() < 2 {
+            return Ok(None);
+        }
+        let first_byte = buf[0];
+        let second_byte = buf[1];
+
+        let fin = (first_byte & 0x80) != 0;
+        let rsv1 = (first_byte & 0x40) != 0;
+        let rsv2 = (first_byte & 0x20) != 0;
+        let rsv3 = (first_byte & 0x10) != 0;
+        let opcode = OpCode::try_from(first_byte & 0x0F)?;
+
+        let masked = (second_byte & 0x80) != 0;
+        let len_field = second_byte & 0x7F;
+        let ext_len = match len_field {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+
+        let mut offset = 2;
+        if buf.len() < offset + ext_len {
+            return Ok(None);
+        }
+        let payload_len = match ext_len {
+            2 => u16::from_be_bytes([buf[offset], buf[offset + 1]]) as u64,
+            8 => {
+                let mut len_bytes = [0u8; 8];
+                len_bytes.copy_from_slice(&buf[offset..offset + 8]);
+                u64::from_be_bytes(len_bytes)
+            }
+            _ => len_field as u64,
+        };
+        offset += ext_len;
+
+        let mask_len = if masked { 4 } else { 0 };
+        if buf.len() < offset + mask_len {
+            return Ok(None);
+        }
+        let mask = if masked {
+            let mut mask_bytes = [0u8; 4];
+            mask_bytes.copy_from_slice(&buf[offset..offset + 4]);
+            Some(mask_bytes)
+        } else {
+            None
+        };
+        offset += mask_len;
+
+        Ok(Some((
+            FrameHeader { fin, rsv1, rsv2, rsv3, opcode, mask, payload_len },
+            offset,
+        )))
+    }
+}
+
 /// Represents a WebSocket frame.
 #[derive(Debug)]
 pub struct Frame {
@@ -76,9 +245,29 @@ impl Frame {
         }
     }
 
-    /// Creates a close frame with an optional status code.
-    pub fn close(status_code: Option<u16>) -> Self {
-        let payload = status_code.map(|code| code.to_be_bytes().to_vec()).unwrap_or_default();
+    /// The maximum length, in bytes, of a close frame's reason string: the
+    /// RFC 6455 125-byte control-frame limit minus the 2-byte status code.
+    const MAX_REASON_LEN: usize = 123;
+
+    /// Creates a close frame carrying the given status code and reason.
+    ///
+    /// A reason that would push the payload past the RFC 6455 125-byte
+    /// control-frame limit is truncated (at a UTF-8 character boundary)
+    /// rather than rejected; [`ping`](crate::WebSocket::ping), by contrast,
+    /// rejects an overlong payload with an error.
+    pub fn close(reason: CloseReason) -> Self {
+        let mut payload = reason.code.to_be_bytes().to_vec();
+        let reason_bytes = reason.reason.as_bytes();
+        let truncate_at = if reason_bytes.len() > Self::MAX_REASON_LEN {
+            let mut at = Self::MAX_REASON_LEN;
+            while !reason.reason.is_char_boundary(at) {
+                at -= 1;
+            }
+            at
+        } else {
+            reason_bytes.len()
+        };
+        payload.extend_from_slice(&reason_bytes[..truncate_at]);
         Frame::new(OpCode::Close, payload)
     }
 
@@ -92,61 +281,95 @@ impl Frame {
         self.mask.is_some()
     }
 
-    /// Reads a frame from the given AsyncRead stream.
+    /// Parses this close frame's payload into a status code and reason.
+    ///
+    /// Returns `Ok(None)` if the payload is empty, which is a valid close
+    /// frame with no code or reason.
     ///
     /// # Errors
     ///
-    /// Returns an error if reading from the stream fails or if the frame is invalid.
-    pub async fn read_from<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self> {
-        let mut buf = [0u8; 2];
-        reader.read_exact(&mut buf).await?;
-        let first_byte = buf[0];
-        let second_byte = buf[1];
-
-        let fin = (first_byte & 0x80) != 0;
-        let rsv1 = (first_byte & 0x40) != 0;
-        let rsv2 = (first_byte & 0x20) != 0;
-        let rsv3 = (first_byte & 0x10) != 0;
-        let opcode = OpCode::try_from(first_byte & 0x0F)?;
+    /// Returns an error if the payload is shorter than 2 bytes or the
+    /// reason is not valid UTF-8.
+    pub fn close_reason(&self) -> Result<Option<CloseReason>> {
+        if self.payload.is_empty() {
+            return Ok(None);
+        }
+        if self.payload.len() < 2 {
+            return Err(Error::Protocol("Close frame payload too short".into()));
+        }
+        let code = CloseCode::from_be_bytes([self.payload[0], self.payload[1]]);
+        let reason = String::from_utf8(self.payload[2..].to_vec())
+            .map_err(|_| Error::Protocol("Close reason is not valid UTF-8".into()))?;
+        Ok(Some(CloseReason { code, reason }))
+    }
 
-        let masked = (second_byte & 0x80) != 0;
-        let mut payload_len = (second_byte & 0x7F) as u64;
+    /// Reads a frame from the given AsyncRead stream, reading the payload
+    /// into `scratch` (cleared and resized as needed) and unmasking it in
+    /// place.
+    ///
+    /// The filled buffer is then moved into the returned frame's payload,
+    /// leaving `scratch` empty with no capacity, so each call allocates a
+    /// fresh payload buffer; `scratch`'s allocation is not kept between calls.
+    ///
+    /// `max_frame_size` bounds the decoded payload length; a peer
+    /// advertising a larger payload is rejected before any payload bytes
+    /// are allocated or read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading from the stream fails, the frame is
+    /// invalid, or the payload length exceeds `max_frame_size`.
+    pub async fn read_from<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        max_frame_size: u64,
+        scratch: &mut Vec<u8>,
+    ) -> Result<Self> {
+        // The leading two bytes tell us how many more header bytes (extended
+        // length, mask key) follow, so we read them first and then top up
+        // `header_buf` with exactly the rest before decoding.
+        let mut header_buf = [0u8; FrameHeader::MAX_LEN];
+        reader.read_exact(&mut header_buf[..2]).await?;
 
-        if payload_len == 126 {
-            let mut buf = [0u8; 2];
-            reader.read_exact(&mut buf).await?;
-            payload_len = u16::from_be_bytes(buf) as u64;
-        } else if payload_len == 127 {
-            let mut buf = [0u8; 8];
-            reader.read_exact(&mut buf).await?;
-            payload_len = u64::from_be_bytes(buf);
+        let ext_len = match header_buf[1] & 0x7F {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask_len = if header_buf[1] & 0x80 != 0 { 4 } else { 0 };
+        let header_len = 2 + ext_len + mask_len;
+        if header_len > 2 {
+            reader.read_exact(&mut header_buf[2..header_len]).await?;
         }
 
-        let mask = if masked {
-            let mut mask_bytes = [0u8; 4];
-            reader.read_exact(&mut mask_bytes).await?;
-            Some(mask_bytes)
-        } else {
-            None
-        };
+        let (header, consumed) = FrameHeader::try_parse(&header_buf[..header_len])?
+            .expect("header_buf holds exactly one complete header");
+        debug_assert_eq!(consumed, header_len);
 
-        let mut payload = vec![0u8; payload_len as usize];
-        reader.read_exact(&mut payload).await?;
+        if header.payload_len > max_frame_size {
+            return Err(Error::Protocol(format!(
+                "frame too large: {} bytes exceeds the {} byte limit",
+                header.payload_len, max_frame_size
+            )));
+        }
+
+        scratch.clear();
+        scratch.resize(header.payload_len as usize, 0);
+        reader.read_exact(scratch).await?;
 
-        if let Some(mask) = mask {
-            for (i, byte) in payload.iter_mut().enumerate() {
+        if let Some(mask) = header.mask {
+            for (i, byte) in scratch.iter_mut().enumerate() {
                 *byte ^= mask[i % 4];
             }
         }
 
         Ok(Frame {
-            fin,
-            rsv1,
-            rsv2,
-            rsv3,
-            opcode,
-            mask,
-            payload,
+            fin: header.fin,
+            rsv1: header.rsv1,
+            rsv2: header.rsv2,
+            rsv3: header.rsv3,
+            opcode: header.opcode,
+            mask: header.mask,
+            payload: std::mem::take(scratch),
         })
     }
 
@@ -197,3 +420,88 @@ impl Frame {
         bytes
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::io::Cursor;
+
+    #[test]
+    fn close_truncates_overlong_reason_to_the_control_frame_limit() {
+        let reason = CloseReason { code: CloseCode::Normal, reason: "x".repeat(200) };
+        let frame = Frame::close(reason);
+        assert!(frame.payload.len() <= 125);
+        assert_eq!(frame.payload.len(), 2 + Frame::MAX_REASON_LEN);
+    }
+
+    #[test]
+    fn close_truncates_at_a_utf8_character_boundary() {
+        // Each "é" is 2 bytes; 62 of them is 124 bytes, one past the
+        // 123-byte reason limit, so a naive byte-offset truncation would
+        // split the last character and produce invalid UTF-8.
+        let reason = CloseReason { code: CloseCode::Normal, reason: "é".repeat(62) };
+        let frame = Frame::close(reason);
+        let reason_bytes = &frame.payload[2..];
+        assert!(reason_bytes.len() <= Frame::MAX_REASON_LEN);
+        assert!(std::str::from_utf8(reason_bytes).is_ok());
+    }
+
+    #[test]
+    fn try_parse_reports_none_on_partial_header() {
+        // Masked, with a 2-byte extended length: first byte, second byte,
+        // 2 extended-length bytes, 4-byte mask key = 8 header bytes, the
+        // widest header shape short of the 8-byte extended length.
+        let header = [0x82, 0xFE, 0x00, 0xFF, 0xAA, 0xBB, 0xCC, 0xDD];
+        for end in 0..header.len() {
+            assert!(
+                FrameHeader::try_parse(&header[..end]).unwrap().is_none(),
+                "expected Ok(None) for a {}-byte prefix of an {}-byte header",
+                end,
+                header.len()
+            );
+        }
+        let (parsed, consumed) = FrameHeader::try_parse(&header).unwrap().unwrap();
+        assert_eq!(consumed, header.len());
+        assert_eq!(parsed.payload_len, 0x00FF);
+        assert_eq!(parsed.mask, Some([0xAA, 0xBB, 0xCC, 0xDD]));
+    }
+
+    #[test]
+    fn read_from_hands_scratch_buffer_ownership_to_the_payload() {
+        smol::block_on(async {
+            let bytes = Frame::new(OpCode::Binary, b"payload one".to_vec()).to_bytes();
+            let mut reader = Cursor::new(bytes);
+            let mut scratch = Vec::new();
+
+            let frame = Frame::read_from(&mut reader, DEFAULT_MAX_FRAME_SIZE, &mut scratch)
+                .await
+                .unwrap();
+
+            assert_eq!(frame.payload, b"payload one");
+            // A regression to `scratch.to_vec()` would copy the payload out
+            // and leave `scratch` still holding it; taking ownership should
+            // leave `scratch` empty instead.
+            assert!(scratch.is_empty());
+        });
+    }
+
+    #[test]
+    fn read_from_reuses_scratch_buffer_across_calls() {
+        smol::block_on(async {
+            let mut stream = Frame::new(OpCode::Binary, b"first".to_vec()).to_bytes();
+            stream.extend(Frame::new(OpCode::Binary, b"second".to_vec()).to_bytes());
+            let mut reader = Cursor::new(stream);
+            let mut scratch = Vec::new();
+
+            let first = Frame::read_from(&mut reader, DEFAULT_MAX_FRAME_SIZE, &mut scratch)
+                .await
+                .unwrap();
+            let second = Frame::read_from(&mut reader, DEFAULT_MAX_FRAME_SIZE, &mut scratch)
+                .await
+                .unwrap();
+
+            assert_eq!(first.payload, b"first");
+            assert_eq!(second.payload, b"second");
+        });
+    }
+}
@@ -8,12 +8,123 @@ use crate::{Error, Result};
 
 const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+/// Configuration for the WebSocket opening handshake.
+///
+/// On the client side this controls the request path/host, the extra
+/// headers sent, and the ordered list of subprotocols offered. On the
+/// server side, `protocols` is instead the set of subprotocols the server
+/// is willing to speak.
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig {
+    path: String,
+    host: String,
+    protocols: Vec<String>,
+    headers: Vec<(String, String)>,
+    deflate: bool,
+    client_no_context_takeover: bool,
+    server_no_context_takeover: bool,
+}
+
+impl HandshakeConfig {
+    /// Creates a new handshake configuration with default path and host.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the request path sent by the client (default `/`).
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = path.into();
+        self
+    }
+
+    /// Sets the `Host` header sent by the client.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// Adds a subprotocol: on the client, offered in order of preference;
+    /// on the server, added to the set of supported subprotocols.
+    pub fn protocol(mut self, protocol: impl Into<String>) -> Self {
+        self.protocols.push(protocol.into());
+        self
+    }
+
+    /// Adds an extra header to the client's handshake request.
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Enables the `permessage-deflate` extension: offered by the client,
+    /// or accepted by the server if the client offers it.
+    pub fn permessage_deflate(mut self) -> Self {
+        self.deflate = true;
+        self
+    }
+
+    /// Requests that the client not reuse its compression context between
+    /// messages (`client_no_context_takeover`). On the client, this is
+    /// offered in the request; on the server, this unilaterally forces the
+    /// parameter on in the response even if the client didn't offer it,
+    /// exactly like [`server_no_context_takeover`](Self::server_no_context_takeover)
+    /// already does for its own parameter.
+    pub fn client_no_context_takeover(mut self) -> Self {
+        self.client_no_context_takeover = true;
+        self
+    }
+
+    /// Requests that the server not reuse its compression context between
+    /// messages (`server_no_context_takeover`). On the client, this is
+    /// offered in the request; on the server, this unilaterally forces the
+    /// parameter on in the response even if the client didn't offer it.
+    pub fn server_no_context_takeover(mut self) -> Self {
+        self.server_no_context_takeover = true;
+        self
+    }
+}
+
+impl Default for HandshakeConfig {
+    fn default() -> Self {
+        Self {
+            path: "/".to_string(),
+            host: "server.example.com".to_string(),
+            protocols: Vec::new(),
+            headers: Vec::new(),
+            deflate: false,
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
+        }
+    }
+}
+
+/// Negotiated `permessage-deflate` parameters, present when both ends agreed
+/// to use the extension.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeflateParams {
+    /// Whether the client resets its compression context between messages.
+    pub client_no_context_takeover: bool,
+    /// Whether the server resets its compression context between messages.
+    pub server_no_context_takeover: bool,
+}
+
+/// The negotiated outcome of a handshake.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeOutcome {
+    /// The subprotocol selected, if any.
+    pub protocol: Option<String>,
+    /// The negotiated `permessage-deflate` parameters, if the extension was
+    /// agreed on.
+    pub deflate: Option<DeflateParams>,
+}
+
 /// Performs the server-side WebSocket handshake.
 ///
 /// # Errors
 ///
-/// Returns an error if the handshake fails.
-pub async fn server_handshake<S>(stream: &mut S) -> Result<()>
+/// Returns an error if the handshake fails, or if the client's request is
+/// missing or misrepresents the required upgrade headers.
+pub async fn server_handshake<S>(stream: &mut S, config: &HandshakeConfig) -> Result<HandshakeOutcome>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
@@ -34,43 +145,95 @@ where
         }
     }
 
+    require_header_value(&headers, "upgrade", "websocket")?;
+    require_header_contains(&headers, "connection", "upgrade")?;
+    require_header_value(&headers, "sec-websocket-version", "13")?;
+
     let key = headers.get("sec-websocket-key").ok_or_else(|| {
         Error::Protocol("Missing Sec-WebSocket-Key header".into())
     })?;
 
     let response_key = generate_accept_value(key);
+    let negotiated_protocol = headers
+        .get("sec-websocket-protocol")
+        .and_then(|offered| select_protocol(offered, &config.protocols));
+
+    let deflate = if config.deflate {
+        headers
+            .get("sec-websocket-extensions")
+            .and_then(|offered| find_extension_offer(offered, "permessage-deflate"))
+            .map(|params| DeflateParams {
+                client_no_context_takeover: params.iter().any(|p| p == "client_no_context_takeover")
+                    || config.client_no_context_takeover,
+                server_no_context_takeover: params.iter().any(|p| p == "server_no_context_takeover")
+                    || config.server_no_context_takeover,
+            })
+    } else {
+        None
+    };
 
-    let response = format!(
+    let mut response = format!(
         "HTTP/1.1 101 Switching Protocols\r\n\
          Upgrade: websocket\r\n\
          Connection: Upgrade\r\n\
-         Sec-WebSocket-Accept: {}\r\n\r\n",
+         Sec-WebSocket-Accept: {}\r\n",
         response_key
     );
+    if let Some(protocol) = &negotiated_protocol {
+        response.push_str(&format!("Sec-WebSocket-Protocol: {}\r\n", protocol));
+    }
+    if let Some(deflate) = &deflate {
+        response.push_str(&format!(
+            "Sec-WebSocket-Extensions: {}\r\n",
+            format_deflate_offer(deflate)
+        ));
+    }
+    response.push_str("\r\n");
 
     buf_reader.get_mut().write_all(response.as_bytes()).await?;
-    Ok(())
+    Ok(HandshakeOutcome { protocol: negotiated_protocol, deflate })
 }
 
 /// Performs the client-side WebSocket handshake.
 ///
 /// # Errors
 ///
-/// Returns an error if the handshake fails.
-pub async fn client_handshake<S>(stream: &mut S) -> Result<()>
+/// Returns an error if the handshake fails, the server selects a
+/// subprotocol the client did not offer, or the server accepts the
+/// `permessage-deflate` extension when the client did not offer it.
+pub async fn client_handshake<S>(stream: &mut S, config: &HandshakeConfig) -> Result<HandshakeOutcome>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     let key = generate_random_key();
-    let request = format!(
-        "GET / HTTP/1.1\r\n\
-         Host: server.example.com\r\n\
+    let mut request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
          Upgrade: websocket\r\n\
          Connection: Upgrade\r\n\
          Sec-WebSocket-Key: {}\r\n\
-         Sec-WebSocket-Version: 13\r\n\r\n",
-        key
+         Sec-WebSocket-Version: 13\r\n",
+        config.path, config.host, key
     );
+    if !config.protocols.is_empty() {
+        request.push_str(&format!(
+            "Sec-WebSocket-Protocol: {}\r\n",
+            config.protocols.join(", ")
+        ));
+    }
+    if config.deflate {
+        request.push_str(&format!(
+            "Sec-WebSocket-Extensions: {}\r\n",
+            format_deflate_offer(&DeflateParams {
+                client_no_context_takeover: config.client_no_context_takeover,
+                server_no_context_takeover: config.server_no_context_takeover,
+            })
+        ));
+    }
+    for (name, value) in &config.headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
 
     stream.write_all(request.as_bytes()).await?;
 
@@ -100,6 +263,99 @@ where
         return Err(Error::Protocol("Invalid Sec-WebSocket-Accept value".into()));
     }
 
+    let negotiated_protocol = match headers.get("sec-websocket-protocol") {
+        Some(protocol) => {
+            if !config.protocols.iter().any(|offered| offered == protocol) {
+                return Err(Error::Protocol(format!(
+                    "Server selected a subprotocol we did not offer: {}",
+                    protocol
+                )));
+            }
+            Some(protocol.clone())
+        }
+        None => None,
+    };
+
+    let deflate = match headers
+        .get("sec-websocket-extensions")
+        .and_then(|accepted| find_extension_offer(accepted, "permessage-deflate"))
+    {
+        Some(params) => {
+            if !config.deflate {
+                return Err(Error::Protocol(
+                    "Server accepted permessage-deflate but we did not offer it".into(),
+                ));
+            }
+            Some(DeflateParams {
+                client_no_context_takeover: params.iter().any(|p| p == "client_no_context_takeover"),
+                server_no_context_takeover: params.iter().any(|p| p == "server_no_context_takeover"),
+            })
+        }
+        None => None,
+    };
+
+    Ok(HandshakeOutcome { protocol: negotiated_protocol, deflate })
+}
+
+/// Finds an extension named `name` among the comma-separated offers in a
+/// `Sec-WebSocket-Extensions` header value, returning its `;`-separated
+/// parameter list (lowercased, trimmed) if present.
+fn find_extension_offer(header_value: &str, name: &str) -> Option<Vec<String>> {
+    header_value.split(',').find_map(|offer| {
+        let mut parts = offer.split(';').map(|p| p.trim().to_lowercase());
+        let offer_name = parts.next()?;
+        if offer_name == name {
+            Some(parts.collect())
+        } else {
+            None
+        }
+    })
+}
+
+/// Formats the `permessage-deflate` extension offer/acceptance for a
+/// `Sec-WebSocket-Extensions` header value.
+fn format_deflate_offer(params: &DeflateParams) -> String {
+    let mut offer = "permessage-deflate".to_string();
+    if params.client_no_context_takeover {
+        offer.push_str("; client_no_context_takeover");
+    }
+    if params.server_no_context_takeover {
+        offer.push_str("; server_no_context_takeover");
+    }
+    offer
+}
+
+/// Picks the first client-offered protocol (comma-separated) present in
+/// `supported`, if any.
+fn select_protocol(offered: &str, supported: &[String]) -> Option<String> {
+    offered
+        .split(',')
+        .map(|p| p.trim())
+        .find(|p| supported.iter().any(|s| s == p))
+        .map(String::from)
+}
+
+/// Requires that `name` is present in `headers` and equals `expected`
+/// (case-insensitively).
+fn require_header_value(headers: &HashMap<String, String>, name: &str, expected: &str) -> Result<()> {
+    let value = headers
+        .get(name)
+        .ok_or_else(|| Error::Protocol(format!("Missing {} header", name)))?;
+    if !value.eq_ignore_ascii_case(expected) {
+        return Err(Error::Protocol(format!("Invalid {} header: {}", name, value)));
+    }
+    Ok(())
+}
+
+/// Requires that `name` is present in `headers` and its comma-separated
+/// value list contains `expected` (case-insensitively).
+fn require_header_contains(headers: &HashMap<String, String>, name: &str, expected: &str) -> Result<()> {
+    let value = headers
+        .get(name)
+        .ok_or_else(|| Error::Protocol(format!("Missing {} header", name)))?;
+    if !value.split(',').any(|part| part.trim().eq_ignore_ascii_case(expected)) {
+        return Err(Error::Protocol(format!("Invalid {} header: {}", name, value)));
+    }
     Ok(())
 }
 
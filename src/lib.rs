@@ -5,11 +5,16 @@ use smol::prelude::*;
 use std::pin::Pin;
 use thiserror::Error;
 
+mod deflate;
 mod frame;
 mod handshake;
 mod mask;
+mod message;
 
-pub use frame::{Frame, OpCode};
+use deflate::{DecompressError, PerMessageDeflate};
+pub use frame::{CloseCode, CloseReason, Frame, FrameHeader, OpCode, DEFAULT_MAX_FRAME_SIZE};
+pub use handshake::HandshakeConfig;
+pub use message::Message;
 use handshake::{client_handshake, server_handshake};
 
 /// Represents errors that can occur in WebSocket operations.
@@ -33,34 +38,122 @@ pub type Result<T> = std::result::Result<T, Error>;
 pub struct WebSocket<S> {
     stream: S,
     is_client: bool,
+    /// Partially-received message from an unfinished sequence of fragments,
+    /// along with whether its first frame had `rsv1` (compression) set.
+    fragment: Option<(OpCode, bool, Vec<u8>)>,
+    /// The maximum allowed frame payload (and, for `receive_message`,
+    /// reassembled message) size, in bytes.
+    max_frame_size: u64,
+    /// The subprotocol negotiated during the handshake, if any.
+    protocol: Option<String>,
+    /// permessage-deflate compression state, if the extension was negotiated.
+    deflate: Option<PerMessageDeflate>,
+    /// Callback invoked with the payload of each unsolicited `Pong` consumed
+    /// by `receive_message`.
+    pong_handler: Option<Box<dyn FnMut(Vec<u8>) + Send>>,
+    /// Scratch buffer passed to `Frame::read_from` on each call to `receive`
+    /// for the incoming frame payload. `read_from` moves its contents into
+    /// the returned frame, so it is empty between calls and each frame's
+    /// payload is a fresh allocation.
+    read_buf: Vec<u8>,
 }
 
 impl<S> WebSocket<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
-    /// Accepts a WebSocket connection as a server.
+    /// Accepts a WebSocket connection as a server, negotiating the
+    /// handshake according to `config`.
     ///
     /// # Errors
     ///
     /// Returns an error if the handshake fails.
-    pub async fn accept(stream: S) -> Result<Self> {
-        let mut ws = WebSocket { stream, is_client: false };
-        server_handshake(&mut ws.stream).await?;
+    pub async fn accept(stream: S, config: HandshakeConfig) -> Result<Self> {
+        let mut ws = WebSocket {
+            stream,
+            is_client: false,
+            fragment: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            protocol: None,
+            deflate: None,
+            pong_handler: None,
+            read_buf: Vec::new(),
+        };
+        let outcome = server_handshake(&mut ws.stream, &config).await?;
+        ws.protocol = outcome.protocol;
+        // The server compresses what it sends (bound by `server_no_context_takeover`)
+        // and decompresses what the client sends (bound by `client_no_context_takeover`).
+        ws.deflate = outcome
+            .deflate
+            .map(|d| PerMessageDeflate::new(d.server_no_context_takeover, d.client_no_context_takeover));
         Ok(ws)
     }
 
-    /// Connects to a WebSocket server as a client.
+    /// Connects to a WebSocket server as a client, negotiating the
+    /// handshake according to `config`.
     ///
     /// # Errors
     ///
     /// Returns an error if the handshake fails.
-    pub async fn connect(stream: S) -> Result<Self> {
-        let mut ws = WebSocket { stream, is_client: true };
-        client_handshake(&mut ws.stream).await?;
+    pub async fn connect(stream: S, config: HandshakeConfig) -> Result<Self> {
+        let mut ws = WebSocket {
+            stream,
+            is_client: true,
+            fragment: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            protocol: None,
+            deflate: None,
+            pong_handler: None,
+            read_buf: Vec::new(),
+        };
+        let outcome = client_handshake(&mut ws.stream, &config).await?;
+        ws.protocol = outcome.protocol;
+        // The client compresses what it sends (bound by `client_no_context_takeover`)
+        // and decompresses what the server sends (bound by `server_no_context_takeover`).
+        ws.deflate = outcome
+            .deflate
+            .map(|d| PerMessageDeflate::new(d.client_no_context_takeover, d.server_no_context_takeover));
         Ok(ws)
     }
 
+    /// Returns the subprotocol negotiated during the handshake, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
+    /// Sets the maximum allowed frame payload size, in bytes.
+    ///
+    /// This also bounds the total size of a reassembled message in
+    /// [`receive_message`](Self::receive_message). Defaults to
+    /// [`DEFAULT_MAX_FRAME_SIZE`].
+    pub fn with_max_frame_size(mut self, max_frame_size: u64) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Registers a callback invoked with the payload of each unsolicited
+    /// `Pong` consumed by [`receive_message`](Self::receive_message).
+    pub fn with_pong_handler<F>(mut self, handler: F) -> Self
+    where
+        F: FnMut(Vec<u8>) + Send + 'static,
+    {
+        self.pong_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Sends a ping with the given payload.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `payload` exceeds the RFC 6455 control-frame
+    /// limit of 125 bytes, or if sending fails.
+    pub async fn ping(&mut self, payload: Vec<u8>) -> Result<()> {
+        if payload.len() > 125 {
+            return Err(Error::Protocol("ping payload must be at most 125 bytes".into()));
+        }
+        self.send(Frame::new(OpCode::Ping, payload)).await
+    }
+
     /// Sends a WebSocket frame.
     ///
     /// # Errors
@@ -77,27 +170,216 @@ where
 
     /// Receives a WebSocket frame.
     ///
+    /// Protocol violations (a malformed frame, or a frame masked/unmasked
+    /// contrary to its direction) trigger an automatic outgoing close frame
+    /// before the error is returned.
+    ///
     /// # Errors
     ///
     /// Returns an error if receiving the frame fails or if the frame is invalid.
     pub async fn receive(&mut self) -> Result<Frame> {
-        let frame = Frame::read_from(&mut self.stream).await?;
-        if !self.is_client && frame.is_masked() {
-            return Err(Error::Protocol("Client frames must be masked".into()));
+        let frame = match Frame::read_from(&mut self.stream, self.max_frame_size, &mut self.read_buf).await {
+            Ok(frame) => frame,
+            Err(Error::Protocol(message)) => {
+                return Err(self.protocol_violation(CloseCode::ProtocolError, message).await);
+            }
+            Err(e) => return Err(e),
+        };
+        if !self.is_client && !frame.is_masked() {
+            return Err(self
+                .protocol_violation(CloseCode::ProtocolError, "client frames must be masked")
+                .await);
+        }
+        if self.is_client && frame.is_masked() {
+            return Err(self
+                .protocol_violation(CloseCode::ProtocolError, "server frames must not be masked")
+                .await);
+        }
+        let rsv1_allowed = self.deflate.is_some()
+            && matches!(frame.opcode, OpCode::Text | OpCode::Binary);
+        if frame.rsv1 && !rsv1_allowed {
+            return Err(self
+                .protocol_violation(
+                    CloseCode::ProtocolError,
+                    "RSV1 is only valid on a data frame when permessage-deflate is negotiated",
+                )
+                .await);
         }
-        if self.is_client && !frame.is_masked() {
-            return Err(Error::Protocol("Server frames must not be masked".into()));
+        let is_control = matches!(frame.opcode, OpCode::Ping | OpCode::Pong | OpCode::Close);
+        if is_control && (!frame.fin || frame.payload.len() > 125) {
+            return Err(self
+                .protocol_violation(
+                    CloseCode::ProtocolError,
+                    "control frames must not be fragmented and must be at most 125 bytes",
+                )
+                .await);
         }
         Ok(frame)
     }
 
-    /// Closes the WebSocket connection.
+    /// Sends an automatic close frame for a detected protocol violation and
+    /// returns the corresponding error.
+    ///
+    /// Sending the close frame is best-effort: if it fails, the original
+    /// protocol error is still returned.
+    async fn protocol_violation(&mut self, code: CloseCode, message: impl Into<String>) -> Error {
+        let message = message.into();
+        let close_frame = Frame::close(CloseReason { code, reason: message.clone() });
+        let _ = self.send(close_frame).await;
+        Error::Protocol(message)
+    }
+
+    /// Sends a high-level [`Message`], encoding it as a single unfragmented
+    /// frame.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if sending the frame fails.
+    pub async fn send_message(&mut self, message: Message) -> Result<()> {
+        let frame = match message {
+            Message::Text(text) => self.encode_data_frame(OpCode::Text, text.into_bytes()),
+            Message::Binary(data) => self.encode_data_frame(OpCode::Binary, data),
+            Message::Ping(data) => Frame::new(OpCode::Ping, data),
+            Message::Pong(data) => Frame::new(OpCode::Pong, data),
+            Message::Close(reason) => {
+                let reason = reason.unwrap_or(CloseReason { code: CloseCode::Normal, reason: String::new() });
+                Frame::close(reason)
+            }
+        };
+        self.send(frame).await
+    }
+
+    /// Builds a single unfragmented data frame, compressing the payload and
+    /// setting `rsv1` if permessage-deflate was negotiated.
+    fn encode_data_frame(&mut self, opcode: OpCode, payload: Vec<u8>) -> Frame {
+        match &mut self.deflate {
+            Some(deflate) => {
+                let mut frame = Frame::new(opcode, deflate.compress(&payload));
+                frame.rsv1 = true;
+                frame
+            }
+            None => Frame::new(opcode, payload),
+        }
+    }
+
+    /// Receives a high-level [`Message`], transparently reassembling
+    /// fragmented data messages.
+    ///
+    /// Incoming `Ping`s are answered with a `Pong` echoing the same payload,
+    /// and incoming `Pong`s are consumed silently (surfaced only via
+    /// [`with_pong_handler`](Self::with_pong_handler)); neither is returned
+    /// to the caller. `Close` is returned as-is. Any of these may arrive
+    /// between the fragments of a data message without disturbing the
+    /// in-progress reassembly, which resumes on the next call.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if receiving a frame fails, a continuation frame
+    /// arrives with no message in progress, a new data frame arrives while
+    /// one is already in progress, or a completed text message is not valid
+    /// UTF-8.
+    pub async fn receive_message(&mut self) -> Result<Message> {
+        loop {
+            let frame = self.receive().await?;
+            match frame.opcode {
+                OpCode::Text | OpCode::Binary => {
+                    if self.fragment.is_some() {
+                        return Err(self
+                            .protocol_violation(
+                                CloseCode::ProtocolError,
+                                "received a new data frame while a message was already in progress",
+                            )
+                            .await);
+                    }
+                    if frame.fin {
+                        return self.finish_message(frame.opcode, frame.rsv1, frame.payload).await;
+                    }
+                    self.fragment = Some((frame.opcode, frame.rsv1, frame.payload));
+                }
+                OpCode::Continuation => {
+                    let Some((opcode, compressed, mut payload)) = self.fragment.take() else {
+                        return Err(self
+                            .protocol_violation(
+                                CloseCode::ProtocolError,
+                                "received a continuation frame with no message in progress",
+                            )
+                            .await);
+                    };
+                    payload.extend_from_slice(&frame.payload);
+                    if payload.len() as u64 > self.max_frame_size {
+                        return Err(self
+                            .protocol_violation(
+                                CloseCode::ProtocolError,
+                                format!("message too large: exceeds the {} byte limit", self.max_frame_size),
+                            )
+                            .await);
+                    }
+                    if frame.fin {
+                        return self.finish_message(opcode, compressed, payload).await;
+                    }
+                    self.fragment = Some((opcode, compressed, payload));
+                }
+                OpCode::Ping => {
+                    self.send(Frame::new(OpCode::Pong, frame.payload)).await?;
+                }
+                OpCode::Pong => {
+                    if let Some(handler) = self.pong_handler.as_mut() {
+                        handler(frame.payload);
+                    }
+                }
+                OpCode::Close => return Ok(Message::Close(frame.close_reason()?)),
+            }
+        }
+    }
+
+    /// Builds the final [`Message`] for a completed (non-control) data
+    /// message, inflating `payload` first if `compressed` (the message's
+    /// first frame had `rsv1` set), and sending an automatic close frame if
+    /// inflation or UTF-8 validation fails.
+    async fn finish_message(&mut self, opcode: OpCode, compressed: bool, payload: Vec<u8>) -> Result<Message> {
+        let payload = if compressed {
+            // `receive` already rejects `rsv1` unless permessage-deflate was negotiated.
+            let deflate = self.deflate.as_mut().expect("compressed frame without negotiated deflate");
+            match deflate.decompress(&payload, self.max_frame_size) {
+                Ok(inflated) => inflated,
+                Err(DecompressError::TooLarge { limit }) => {
+                    return Err(self
+                        .protocol_violation(
+                            CloseCode::ProtocolError,
+                            format!("decompressed message too large: exceeds the {} byte limit", limit),
+                        )
+                        .await);
+                }
+                Err(DecompressError::Inflate(_)) => {
+                    return Err(self
+                        .protocol_violation(CloseCode::ProtocolError, "failed to inflate compressed message")
+                        .await);
+                }
+            }
+        } else {
+            payload
+        };
+        match opcode {
+            OpCode::Text => match String::from_utf8(payload) {
+                Ok(text) => Ok(Message::Text(text)),
+                Err(_) => Err(self
+                    .protocol_violation(CloseCode::InvalidData, "text message is not valid UTF-8")
+                    .await),
+            },
+            OpCode::Binary => Ok(Message::Binary(payload)),
+            _ => unreachable!("finish_message is only called with a data opcode"),
+        }
+    }
+
+    /// Closes the WebSocket connection, sending the given close code and
+    /// reason and then draining incoming frames until the peer's close
+    /// frame arrives.
     ///
     /// # Errors
     ///
     /// Returns an error if closing the connection fails.
-    pub async fn close(mut self) -> Result<()> {
-        let close_frame = Frame::close(None);
+    pub async fn close(mut self, reason: CloseReason) -> Result<()> {
+        let close_frame = Frame::close(reason);
         self.send(close_frame).await?;
         // Wait for the close frame from the other side
         loop {
@@ -145,3 +427,179 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocket<S> {
         Pin::new(&mut self.stream).poll_close(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use smol::io::Cursor;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::task::{Context, Poll};
+
+    /// An in-memory duplex stream for testing: reads are served from a
+    /// fixed, pre-scripted input buffer and writes are appended to a
+    /// separate, shared output buffer, so writing a reply doesn't disturb
+    /// the not-yet-read remainder of the script (unlike a single shared
+    /// `Cursor`, whose read and write positions coincide).
+    struct MockStream {
+        input: Cursor<Vec<u8>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl AsyncRead for MockStream {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<std::io::Result<usize>> {
+            Pin::new(&mut self.input).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for MockStream {
+        fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.output.borrow_mut().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Builds the wire bytes of a single masked frame, as a real client
+    /// would send to a server `WebSocket` (which requires masked input).
+    fn masked_frame(opcode: OpCode, fin: bool, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut first_byte = if fin { 0x80 } else { 0 };
+        first_byte |= opcode as u8;
+        out.push(first_byte);
+
+        let mask = [0x12u8, 0x34, 0x56, 0x78];
+        let len = payload.len();
+        if len < 126 {
+            out.push(0x80 | len as u8);
+        } else if len < 65536 {
+            out.push(0x80 | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        } else {
+            out.push(0x80 | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+        out.extend_from_slice(&mask);
+        out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        out
+    }
+
+    /// Parses every unmasked frame a server `WebSocket` wrote to `bytes`,
+    /// in order, as `(opcode, payload)` pairs.
+    fn parse_frames(bytes: &[u8]) -> Vec<(OpCode, Vec<u8>)> {
+        let mut frames = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let (header, header_len) = FrameHeader::try_parse(&bytes[offset..]).unwrap().unwrap();
+            let payload_start = offset + header_len;
+            let payload_end = payload_start + header.payload_len as usize;
+            frames.push((header.opcode, bytes[payload_start..payload_end].to_vec()));
+            offset = payload_end;
+        }
+        frames
+    }
+
+    /// Builds a server-side `WebSocket` (so incoming frames must be masked)
+    /// wired directly to `input`/`output`, bypassing the handshake.
+    fn server_socket(input: Vec<u8>, max_frame_size: u64) -> (WebSocket<MockStream>, Rc<RefCell<Vec<u8>>>) {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        let stream = MockStream { input: Cursor::new(input), output: output.clone() };
+        let ws = WebSocket {
+            stream,
+            is_client: false,
+            fragment: None,
+            max_frame_size,
+            protocol: None,
+            deflate: None,
+            pong_handler: None,
+            read_buf: Vec::new(),
+        };
+        (ws, output)
+    }
+
+    #[test]
+    fn receive_message_auto_replies_to_ping_and_returns_the_next_message() {
+        smol::block_on(async {
+            let mut input = masked_frame(OpCode::Ping, true, b"are you there");
+            input.extend(masked_frame(OpCode::Text, true, b"hello"));
+            let (mut ws, output) = server_socket(input, DEFAULT_MAX_FRAME_SIZE);
+
+            let message = ws.receive_message().await.unwrap();
+
+            assert_eq!(message, Message::Text("hello".into()));
+            let written = parse_frames(&output.borrow());
+            assert_eq!(written, vec![(OpCode::Pong, b"are you there".to_vec())]);
+        });
+    }
+
+    #[test]
+    fn receive_message_answers_a_ping_that_arrives_mid_fragment() {
+        smol::block_on(async {
+            let mut input = masked_frame(OpCode::Text, false, b"Hel");
+            input.extend(masked_frame(OpCode::Ping, true, b"ping"));
+            input.extend(masked_frame(OpCode::Continuation, true, b"lo"));
+            let (mut ws, output) = server_socket(input, DEFAULT_MAX_FRAME_SIZE);
+
+            let message = ws.receive_message().await.unwrap();
+
+            assert_eq!(message, Message::Text("Hello".into()));
+            let written = parse_frames(&output.borrow());
+            assert_eq!(written, vec![(OpCode::Pong, b"ping".to_vec())]);
+        });
+    }
+
+    #[test]
+    fn receive_message_rejects_a_continuation_with_no_message_in_progress() {
+        smol::block_on(async {
+            let input = masked_frame(OpCode::Continuation, true, b"orphan");
+            let (mut ws, output) = server_socket(input, DEFAULT_MAX_FRAME_SIZE);
+
+            let err = ws.receive_message().await.unwrap_err();
+
+            match err {
+                Error::Protocol(message) => {
+                    assert_eq!(message, "received a continuation frame with no message in progress")
+                }
+                other => panic!("expected Error::Protocol, got {other:?}"),
+            }
+            let written = parse_frames(&output.borrow());
+            assert_eq!(written.len(), 1);
+            assert_eq!(written[0].0, OpCode::Close);
+        });
+    }
+
+    #[test]
+    fn receive_rejects_a_frame_larger_than_max_frame_size() {
+        smol::block_on(async {
+            let input = masked_frame(OpCode::Binary, true, &[0u8; 100]);
+            let (mut ws, output) = server_socket(input, 10);
+
+            let err = ws.receive_message().await.unwrap_err();
+
+            assert!(matches!(err, Error::Protocol(_)));
+            let written = parse_frames(&output.borrow());
+            assert_eq!(written.len(), 1);
+            assert_eq!(written[0].0, OpCode::Close);
+        });
+    }
+
+    #[test]
+    fn close_sends_a_close_frame_and_drains_until_the_peers_close_arrives() {
+        smol::block_on(async {
+            let input = masked_frame(OpCode::Close, true, &[]);
+            let (ws, output) = server_socket(input, DEFAULT_MAX_FRAME_SIZE);
+
+            ws.close(CloseReason { code: CloseCode::Normal, reason: "bye".into() }).await.unwrap();
+
+            let written = parse_frames(&output.borrow());
+            assert_eq!(written.len(), 1);
+            assert_eq!(written[0].0, OpCode::Close);
+            assert_eq!(&written[0].1[2..], b"bye");
+        });
+    }
+}
@@ -0,0 +1,22 @@
+//! High-level WebSocket messages.
+
+use crate::CloseReason;
+
+/// A complete, reassembled WebSocket message.
+///
+/// Unlike a [`Frame`](crate::Frame), a `Message` has already had any
+/// fragmentation (continuation frames) resolved and, for [`Message::Text`],
+/// been validated as UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A complete UTF-8 text message.
+    Text(String),
+    /// A complete binary message.
+    Binary(Vec<u8>),
+    /// A ping control message.
+    Ping(Vec<u8>),
+    /// A pong control message.
+    Pong(Vec<u8>),
+    /// A close message, with an optional code and reason.
+    Close(Option<CloseReason>),
+}